@@ -14,9 +14,15 @@
 // You should have received a copy of the GNU General Public License
 // along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::Arc;
+use std::thread;
 
 use lru::LruCache;
+use parking_lot::RwLock;
 use rand::{seq::SliceRandom, thread_rng};
 
 use sp_application_crypto::AppKey;
@@ -37,6 +43,267 @@ use super::{
 	Error,
 };
 
+/// How many sessions' worth of resolved keys `AuthorityDiscoveryCache` keeps around.
+///
+/// Several independent `SessionCache`s (one per subsystem sharing this cache) each keep a
+/// handful of sessions (current, last, prefetched-next) alive in their own `session_info_cache`,
+/// so this needs a bit of headroom over that; it does not need to track any one of them exactly,
+/// it only needs to keep the process from accumulating one entry per validator per session ever
+/// queried for the lifetime of the node.
+const AUTHORITY_DISCOVERY_CACHE_SESSIONS: usize = 8;
+
+/// Process-wide cache of the (expensive) keystore/crypto lookups `SessionCache` needs.
+///
+/// Figuring out our own `ValidatorIndex` requires a keystore lookup, and every subsystem that
+/// cares about session info (availability-distribution, approval-voting, statement-distribution,
+/// ...) ends up doing it independently. This cache is built once for the node and handed out as a
+/// cheap `Arc` clone, so our index is resolved once per session while that session stays cached.
+/// It also records each session's `AuthorityDiscoveryId`s; these are taken from the session info
+/// that `SessionCache` fetches from the runtime, so the runtime round-trip itself is not avoided.
+#[derive(Clone)]
+pub struct AuthorityDiscoveryCache {
+	inner: Arc<RwLock<AuthorityDiscoveryCacheInner>>,
+}
+
+struct AuthorityDiscoveryCacheInner {
+	/// Resolved keys per session, bounded (like `SessionCache::session_info_cache`) so sessions
+	/// that are no longer relevant to anyone eventually age out instead of accumulating forever.
+	sessions: LruCache<SessionIndex, SessionDiscoveryKeys>,
+}
+
+/// What we know about a single session's validators.
+#[derive(Default)]
+struct SessionDiscoveryKeys {
+	/// Our own `ValidatorIndex` in this session, once resolved.
+	our_index: Option<ValidatorIndex>,
+	/// `AuthorityDiscoveryId` for a given validator in this session.
+	discovery_keys: HashMap<ValidatorIndex, AuthorityDiscoveryId>,
+}
+
+impl Default for AuthorityDiscoveryCache {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl AuthorityDiscoveryCache {
+	pub fn new() -> Self {
+		Self {
+			inner: Arc::new(RwLock::new(AuthorityDiscoveryCacheInner {
+				sessions: LruCache::new(AUTHORITY_DISCOVERY_CACHE_SESSIONS),
+			})),
+		}
+	}
+
+	/// Get our cached `ValidatorIndex` for the given session, if we have already resolved it.
+	fn our_index(&self, session_index: SessionIndex) -> Option<ValidatorIndex> {
+		self.inner
+			.write()
+			.sessions
+			.get(&session_index)
+			.and_then(|s| s.our_index)
+	}
+
+	/// Remember our `ValidatorIndex` for the given session.
+	fn cache_our_index(&self, session_index: SessionIndex, our_index: ValidatorIndex) {
+		let mut inner = self.inner.write();
+		Self::session_entry(&mut inner.sessions, session_index).our_index = Some(our_index);
+	}
+
+	/// Get the cached `AuthorityDiscoveryId` for a validator in a given session, if known.
+	fn discovery_key(
+		&self,
+		session_index: SessionIndex,
+		validator_index: ValidatorIndex,
+	) -> Option<AuthorityDiscoveryId> {
+		self.inner
+			.write()
+			.sessions
+			.get(&session_index)
+			.and_then(|s| s.discovery_keys.get(&validator_index).cloned())
+	}
+
+	/// Remember the `AuthorityDiscoveryId`s for all validators of a given session.
+	fn cache_discovery_keys(
+		&self,
+		session_index: SessionIndex,
+		keys: impl IntoIterator<Item = (ValidatorIndex, AuthorityDiscoveryId)>,
+	) {
+		let mut inner = self.inner.write();
+		Self::session_entry(&mut inner.sessions, session_index)
+			.discovery_keys
+			.extend(keys);
+	}
+
+	/// Get the session's entry, inserting a fresh, empty one if this is the first time we see it.
+	fn session_entry(
+		sessions: &mut LruCache<SessionIndex, SessionDiscoveryKeys>,
+		session_index: SessionIndex,
+	) -> &mut SessionDiscoveryKeys {
+		if !sessions.contains(&session_index) {
+			sessions.put(session_index, SessionDiscoveryKeys::default());
+		}
+		sessions
+			.get_mut(&session_index)
+			.expect("Just inserted if missing. qed.")
+	}
+}
+
+/// Reputation score decays by this factor for every session that passes without a fresh
+/// `report_bad` against the validator, so that old failures fade rather than permanently
+/// condemning a validator that has since recovered.
+const REPUTATION_DECAY_PER_SESSION: f64 = 0.5;
+
+/// A validator's reputation score, as of the last time it was updated.
+#[derive(Clone, Copy)]
+struct ReputationEntry {
+	/// The (not yet decayed for the current session) score. Higher is worse.
+	score: f64,
+	/// The session in which `score` was last updated.
+	last_update: SessionIndex,
+}
+
+impl ReputationEntry {
+	/// The score, decayed for every session that has passed since `last_update`.
+	fn decayed_score(&self, session_index: SessionIndex) -> f64 {
+		let elapsed = session_index.saturating_sub(self.last_update);
+		self.score * REPUTATION_DECAY_PER_SESSION.powi(elapsed as i32)
+	}
+}
+
+/// On-disk record for a single `(AuthorityDiscoveryId, ReputationEntry)` pair: the discovery id's
+/// raw public key bytes, followed by the big-endian score and session index.
+const REPUTATION_RECORD_LEN: usize = 32 + 8 + 4;
+
+/// Persistent, decaying reputation of validators, keyed by their `AuthorityDiscoveryId`.
+///
+/// Unlike the per-session `validator_groups` reordering `report_bad` already does, this tracks
+/// misbehaving validators across session boundaries *and* node restarts -- much like a validator
+/// client persists its slashing-protection state to a durable store rather than trusting a fresh
+/// process to remember nothing. Scores are kept in memory for fast access, loaded from
+/// `store_path` once on construction, and queued to be written back to it on every update (best
+/// effort, see `persist`), so a validator that
+/// is consistently unresponsive keeps a bad score instead of getting a clean slate every time its
+/// `SessionInfo` entry is evicted from the cache or the node restarts.
+#[derive(Clone)]
+pub struct ValidatorReputationCache {
+	inner: Arc<RwLock<ValidatorReputationCacheInner>>,
+}
+
+struct ValidatorReputationCacheInner {
+	scores: HashMap<AuthorityDiscoveryId, ReputationEntry>,
+	/// Channel to the background writer thread. `None` keeps the cache purely in-memory (e.g.
+	/// for tests).
+	persist_tx: Option<SyncSender<Vec<u8>>>,
+}
+
+impl ValidatorReputationCache {
+	/// Create the cache, loading any previously persisted scores from `store_path`.
+	///
+	/// Pass `None` to keep the cache in-memory only; it will then not survive a node restart.
+	pub fn new(store_path: Option<PathBuf>) -> Self {
+		let scores = store_path.as_deref().map(Self::load).unwrap_or_default();
+		let persist_tx = store_path.map(Self::spawn_writer);
+		Self {
+			inner: Arc::new(RwLock::new(ValidatorReputationCacheInner {
+				scores,
+				persist_tx,
+			})),
+		}
+	}
+
+	/// Load previously persisted scores from `path`, if any exist yet.
+	fn load(path: &Path) -> HashMap<AuthorityDiscoveryId, ReputationEntry> {
+		let bytes = match std::fs::read(path) {
+			Ok(bytes) => bytes,
+			// Nothing persisted yet - a brand new node, or a fresh data directory.
+			Err(_) => return HashMap::new(),
+		};
+		bytes
+			.chunks_exact(REPUTATION_RECORD_LEN)
+			.map(|record| {
+				let id = AuthorityDiscoveryId::from_slice(&record[0..32]);
+				let score = f64::from_be_bytes(record[32..40].try_into().expect("8 bytes. qed."));
+				let last_update = SessionIndex::from_be_bytes(
+					record[40..44].try_into().expect("4 bytes. qed."),
+				);
+				(id, ReputationEntry { score, last_update })
+			})
+			.collect()
+	}
+
+	/// Spawn the dedicated background thread that performs the actual (blocking) file writes,
+	/// so `report_bad`/`reset` never wait on disk I/O on the hot path, and a slow write never
+	/// stalls other subsystems sharing this cache through the write lock.
+	fn spawn_writer(path: PathBuf) -> SyncSender<Vec<u8>> {
+		// Bounded to a single pending snapshot: if one is already waiting for the writer, `persist`
+		// below drops the new one and the pending one is written; every later update rewrites the
+		// whole store.
+		let (tx, rx) = sync_channel::<Vec<u8>>(1);
+		thread::spawn(move || {
+			for bytes in rx {
+				let _ = std::fs::write(&path, bytes);
+			}
+		});
+		tx
+	}
+
+	/// Queue the current in-memory scores to be rewritten to the persisted store.
+	///
+	/// Building the snapshot happens under the cache's write lock, but it is just an in-memory
+	/// copy; the actual (blocking) file write happens on the background writer thread. Best
+	/// effort: a failure, or a snapshot dropped because the writer is still busy, only risks
+	/// forgetting this update across a restart, it should never stall or take down the subsystem.
+	fn persist(inner: &ValidatorReputationCacheInner) {
+		let tx = match &inner.persist_tx {
+			Some(tx) => tx,
+			None => return,
+		};
+		let mut bytes = Vec::with_capacity(inner.scores.len() * REPUTATION_RECORD_LEN);
+		for (id, entry) in &inner.scores {
+			bytes.extend_from_slice(&id.to_raw_vec());
+			bytes.extend_from_slice(&entry.score.to_be_bytes());
+			bytes.extend_from_slice(&entry.last_update.to_be_bytes());
+		}
+		let _ = tx.try_send(bytes);
+	}
+
+	/// Record a failure for the given validator in the given session.
+	fn report_bad(&self, id: &AuthorityDiscoveryId, session_index: SessionIndex) {
+		let mut inner = self.inner.write();
+		let decayed = inner
+			.scores
+			.get(id)
+			.map(|entry| entry.decayed_score(session_index))
+			.unwrap_or(0.0);
+		inner.scores.insert(
+			id.clone(),
+			ReputationEntry {
+				score: decayed + 1.0,
+				last_update: session_index,
+			},
+		);
+		Self::persist(&inner);
+	}
+
+	/// The validator's current, decayed score. Higher is worse; unknown validators score 0.
+	pub fn score(&self, id: &AuthorityDiscoveryId, session_index: SessionIndex) -> f64 {
+		self.inner
+			.read()
+			.scores
+			.get(id)
+			.map(|entry| entry.decayed_score(session_index))
+			.unwrap_or(0.0)
+	}
+
+	/// Forget any recorded reputation for the given validator.
+	pub fn reset(&self, id: &AuthorityDiscoveryId) {
+		let mut inner = self.inner.write();
+		inner.scores.remove(id);
+		Self::persist(&inner);
+	}
+}
+
 /// Caching of session info as needed by availability distribution.
 ///
 /// It should be ensured that a cached session stays live in the cache as long as we might need it.
@@ -53,10 +320,20 @@ pub struct SessionCache {
 	/// Note: Performance of fetching is really secondary here, but we need to ensure we are going
 	/// to get any existing cache entry, before fetching new information, as we should not mess up
 	/// the order of validators. (We want live TCP connections wherever possible.)
+	///
+	/// We keep the current and the last session around, plus one extra slot for the upcoming
+	/// session, which `prefetch_next_session_info` may have already populated ahead of time. That
+	/// way a prefetched "next" session never evicts the still-needed "current" and "last" ones.
 	session_info_cache: LruCache<SessionIndex, SessionInfo>,
 
 	/// Key store for determining whether we are a validator and what `ValidatorIndex` we have.
 	keystore: SyncCryptoStorePtr,
+
+	/// Node-wide cache of our validator index and discovery keys, shared with other subsystems.
+	discovery_cache: AuthorityDiscoveryCache,
+
+	/// Node-wide, persistent reputation scores for validators, surviving cache eviction.
+	reputation: ValidatorReputationCache,
 }
 
 /// Localized session information, tailored for the needs of availability distribution.
@@ -90,13 +367,20 @@ pub struct BadValidators {
 }
 
 impl SessionCache {
-	pub fn new(keystore: SyncCryptoStorePtr) -> Self {
+	pub fn new(
+		keystore: SyncCryptoStorePtr,
+		discovery_cache: AuthorityDiscoveryCache,
+		reputation: ValidatorReputationCache,
+	) -> Self {
 		SessionCache {
 			// 5 relatively conservative, 1 to 2 should suffice:
 			session_index_cache: LruCache::new(5),
-			// We need to cache the current and the last session the most:
-			session_info_cache: LruCache::new(2),
+			// We need to cache the current and the last session the most, plus one slot for a
+			// prefetched upcoming session (see `prefetch_next_session_info`):
+			session_info_cache: LruCache::new(3),
 			keystore,
+			discovery_cache,
+			reputation,
 		}
 	}
 
@@ -142,11 +426,59 @@ impl SessionCache {
 		Ok(None)
 	}
 
+	/// Pre-compute and cache the `SessionInfo` of the session following `session_index`.
+	///
+	/// Session rotations happen at well-known boundaries, so callers (typically in response to a
+	/// session-change notification for a newly imported head, or by simply noticing the session
+	/// index of a new leaf is about to roll over) can use this to move the runtime round-trip,
+	/// group shuffle and discovery-key resolution off the hot path: by the time the first chunk
+	/// request of the new session arrives, its `SessionInfo` is already sitting in the cache.
+	///
+	/// `parent` only needs to be some relay parent belonging to the still-current session; it is
+	/// merely passed through to the runtime API.
+	pub async fn prefetch_next_session_info<Context>(
+		&mut self,
+		ctx: &mut Context,
+		parent: Hash,
+		session_index: SessionIndex,
+	) -> Result<()>
+	where
+		Context: SubsystemContext,
+	{
+		let next_session_index = session_index + 1;
+		if self.session_info_cache.contains(&next_session_index) {
+			// Already prefetched (or otherwise populated) - nothing to do.
+			return Ok(());
+		}
+		let info = match self
+			.query_info_from_runtime(ctx, parent, next_session_index)
+			.await
+		{
+			Ok(info) => info,
+			// The runtime may not have the next session queued up yet if we got called right on
+			// the session boundary - this is best-effort prefetching, so just try again later
+			// instead of treating it like a failure on the already-needed current session.
+			Err(Error::NoSuchSession(_)) => return Ok(()),
+			Err(err) => return Err(err),
+		};
+		if let Some(info) = info {
+			self.session_info_cache.put(next_session_index, info);
+		}
+		Ok(())
+	}
+
 	/// Make sure we try unresponsive or misbehaving validators last.
 	///
 	/// We assume validators in a group are tried in reverse order, so the reported bad validators
 	/// will be put at the beginning of the group.
 	pub fn report_bad(&mut self, mut report: BadValidators) -> Result<()> {
+		// Persist the failure beyond this session's in-memory reshuffling, so a validator that is
+		// consistently unresponsive doesn't get a clean slate once its `SessionInfo` is evicted
+		// or the node restarts:
+		for bad_validator in &report.bad_validators {
+			self.reputation.report_bad(bad_validator, report.session_index);
+		}
+
 		let session = self
 			.session_info_cache
 			.get_mut(&report.session_index)
@@ -190,7 +522,17 @@ impl SessionCache {
 			.await?
 			.ok_or(Error::NoSuchSession(session_index))?;
 
-		if let Some(our_index) = self.get_our_index(validators).await {
+		// Share the resolved discovery keys with other subsystems right away, regardless of
+		// whether we end up being a validator in this session or not.
+		self.discovery_cache.cache_discovery_keys(
+			session_index,
+			discovery_keys
+				.iter()
+				.enumerate()
+				.map(|(i, id)| (ValidatorIndex(i as u32), id.clone())),
+		);
+
+		if let Some(our_index) = self.get_our_index(session_index, validators).await {
 			// Get our group index:
 			let our_group = validator_groups
 				.iter()
@@ -207,24 +549,39 @@ impl SessionCache {
 				// TODO: Make sure this is correct and should be enforced:
 				.expect("Every validator should be in a validator group. qed.");
 
-			// Shuffle validators in groups:
+			// Shuffle validators in groups, for load balancing among equally-reputable peers:
 			let mut rng = thread_rng();
 			for g in validator_groups.iter_mut() {
 				g.shuffle(&mut rng)
 			}
 			// Look up `AuthorityDiscoveryId`s right away:
-			let validator_groups: Vec<Vec<_>> = validator_groups
+			let mut validator_groups: Vec<Vec<_>> = validator_groups
 				.into_iter()
 				.map(|group| {
 					group
 						.into_iter()
 						.map(|index| {
-							discovery_keys.get(index.0 as usize)
-							.expect("There should be a discovery key for each validator of each validator group. qed.").clone()
+							self.discovery_cache
+								.discovery_key(session_index, index)
+								.expect("We just cached a discovery key for every validator index. qed.")
 						})
 						.collect()
 				})
 				.collect();
+			// Just like `report_bad` puts freshly reported validators at the front of the group
+			// (since we try validators in reverse order, so the front is tried last), sort by
+			// descending (persisted) reputation score here, worst first, so a validator with a
+			// history of failures starts out being tried last even before we get a chance to
+			// report it again this session. The sort is stable, so the random shuffle above still
+			// decides the order among equal-score peers.
+			for g in validator_groups.iter_mut() {
+				g.sort_by(|a, b| {
+					self.reputation
+						.score(b, session_index)
+						.partial_cmp(&self.reputation.score(a, session_index))
+						.expect("Reputation scores are never NaN. qed.")
+				});
+			}
 
 			let info = SessionInfo {
 				validator_groups,
@@ -240,14 +597,34 @@ impl SessionCache {
 	/// Get our validator id and the validators in the current session.
 	///
 	/// Returns: Ok(None) if we are not a validator.
-	async fn get_our_index(&self, validators: Vec<ValidatorId>) -> Option<ValidatorIndex> {
-		for (i, v) in validators.iter().enumerate() {
-			if CryptoStore::has_keys(&*self.keystore, &[(v.to_raw_vec(), ValidatorId::ID)])
-				.await
-			{
-				return Some(ValidatorIndex(i as u32));
-			}
+	async fn get_our_index(
+		&self,
+		session_index: SessionIndex,
+		validators: Vec<ValidatorId>,
+	) -> Option<ValidatorIndex> {
+		if let Some(our_index) = self.discovery_cache.our_index(session_index) {
+			return Some(our_index);
 		}
-		None
+
+		// Index the session's validators by their raw compressed public key bytes, so turning a
+		// keystore hit into a `ValidatorIndex` is a single hash lookup instead of a scan:
+		let index_by_bytes: HashMap<Vec<u8>, ValidatorIndex> = validators
+			.iter()
+			.enumerate()
+			.map(|(i, v)| (v.to_raw_vec(), ValidatorIndex(i as u32)))
+			.collect();
+
+		// Fetch all of the keys we hold for this key type in one keystore round-trip, rather than
+		// asking "do you have this key?" once per validator in the session:
+		let our_public_keys = CryptoStore::keys(&*self.keystore, ValidatorId::ID)
+			.await
+			.unwrap_or_default();
+
+		let our_index = our_public_keys
+			.iter()
+			.find_map(|public_key| index_by_bytes.get(&public_key.1).copied())?;
+
+		self.discovery_cache.cache_our_index(session_index, our_index);
+		Some(our_index)
 	}
-}
\ No newline at end of file
+}